@@ -7,11 +7,207 @@
 use context::QuirksMode;
 use cssparser::{Parser, SourceLocation, UnicodeRange};
 use error_reporting::{ParseErrorReporter, ContextualParseError};
+use std::cell::{Cell, RefCell};
 use style_traits::{OneOrMoreSeparated, ParseError, ParsingMode, Separator};
 #[cfg(feature = "gecko")]
 use style_traits::{PARSING_MODE_DEFAULT, PARSING_MODE_ALLOW_UNITLESS_LENGTH, PARSING_MODE_ALLOW_ALL_NUMERIC_VALUES};
 use stylesheets::{CssRuleType, Origin, UrlExtraData, Namespaces};
 
+/// An identifier for a single CSS property or at-rule/feature that
+/// `UseCounters` can track, assigned by the property and feature generators.
+///
+/// This is just an index into the relevant `UseCounters` bitset, so it's
+/// cheap to pass around and store.
+pub type CounterId = usize;
+
+/// The number of non-custom CSS properties `UseCounters` has room to track.
+const PROPERTY_COUNT: usize = 512;
+
+/// The number of at-rule/feature ids `UseCounters` has room to track.
+const FEATURE_COUNT: usize = 64;
+
+/// A record of which CSS properties and features have been parsed at least
+/// once, so embedders can get telemetry on what a stylesheet actually
+/// exercises without re-walking the parsed representation.
+///
+/// Each counter is a single bit that is set the first time the corresponding
+/// property or feature is successfully parsed; we don't care how many times,
+/// only whether. Counters are `Cell`-backed rather than atomic because a
+/// `UseCounters` is owned by a single parse (one sheet, one thread) and
+/// merged into a per-document total afterwards, rather than shared and
+/// written from multiple threads concurrently.
+pub struct UseCounters {
+    properties: Box<[Cell<bool>]>,
+    features: Box<[Cell<bool>]>,
+}
+
+impl Default for UseCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UseCounters {
+    /// Create a fresh, all-zero set of counters.
+    pub fn new() -> Self {
+        UseCounters {
+            properties: (0..PROPERTY_COUNT).map(|_| Cell::new(false)).collect(),
+            features: (0..FEATURE_COUNT).map(|_| Cell::new(false)).collect(),
+        }
+    }
+
+    /// Record that the property with the given id was successfully parsed.
+    ///
+    /// `id` must be less than `PROPERTY_COUNT`; debug-asserted rather than
+    /// silently ignored, since an id the property generator knows about but
+    /// we don't have room for would otherwise undercount telemetry without
+    /// any indication something is wrong.
+    pub fn record_property(&self, id: CounterId) {
+        debug_assert!(id < PROPERTY_COUNT, "property id out of range for UseCounters");
+        self.properties[id].set(true);
+    }
+
+    /// Record that the at-rule/feature with the given id was successfully
+    /// parsed.
+    ///
+    /// `id` must be less than `FEATURE_COUNT`; see `record_property`.
+    pub fn record_feature(&self, id: CounterId) {
+        debug_assert!(id < FEATURE_COUNT, "feature id out of range for UseCounters");
+        self.features[id].set(true);
+    }
+
+    /// Merge another set of counters into this one, e.g. to fold a per-sheet
+    /// instance into a per-document total.
+    pub fn merge(&self, other: &UseCounters) {
+        for (ours, theirs) in self.properties.iter().zip(other.properties.iter()) {
+            if theirs.get() {
+                ours.set(true);
+            }
+        }
+        for (ours, theirs) in self.features.iter().zip(other.features.iter()) {
+            if theirs.get() {
+                ours.set(true);
+            }
+        }
+    }
+}
+
+/// Whether a selector list should be parsed as a relative selector list
+/// anchored on an implied parent `&`, and why.
+///
+/// A top-level selector list continues to reject a leading combinator; only
+/// the preludes of nested constructs that imply a parent selector opt in.
+///
+/// This only has a `ForNesting` variant for now; `@scope` (which would also
+/// parse a relative selector list) isn't wired up by anything in this file
+/// yet, so we don't carry a variant for it that nothing ever constructs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ParseRelative {
+    /// This is a top-level selector list; leading combinators are rejected.
+    No,
+    /// We're parsing the prelude of a CSS Nesting nested style rule, so it
+    /// should be parsed as a relative selector list anchored on the implied
+    /// parent `&`.
+    ForNesting,
+}
+
+/// The CSS Nesting state threaded alongside a `ParserContext`.
+///
+/// This records whether we are currently inside a style rule, and therefore
+/// whether a further-nested style rule's prelude should be parsed as a
+/// relative selector list.
+#[derive(Clone, Copy, Debug)]
+pub struct NestingContext {
+    parse_relative: ParseRelative,
+}
+
+impl NestingContext {
+    /// The initial state for top-level parsing, outside of any style rule.
+    pub fn none() -> Self {
+        NestingContext {
+            parse_relative: ParseRelative::No,
+        }
+    }
+
+    /// The relative-selector parse mode that should be used for a selector
+    /// list parsed under this context.
+    pub fn parse_relative(&self) -> ParseRelative {
+        self.parse_relative
+    }
+
+    /// Whether a relative-selector parse mode is active, i.e. whether we're
+    /// nested inside a construct (currently: a style rule) that implies a
+    /// parent `&` selector.
+    pub fn is_nested(&self) -> bool {
+        self.parse_relative != ParseRelative::No
+    }
+}
+
+bitflags! {
+    /// The set of every rule type that encloses the value currently being
+    /// parsed.
+    ///
+    /// Unlike `ParserContext::rule_type`, which only remembers the innermost
+    /// enclosing rule, this accumulates every rule type we have descended
+    /// through, so value parsers can ask "am I anywhere inside a
+    /// `@keyframes` block?" regardless of how deeply nested we are. There is
+    /// no ordering information here; it's a set, not a stack.
+    pub struct CssRuleTypes: u16 {
+        /// Inside a style rule (e.g. `div { ... }`).
+        const STYLE = 1 << (CssRuleType::Style as u16);
+        /// Inside a `@charset` rule.
+        const CHARSET = 1 << (CssRuleType::Charset as u16);
+        /// Inside a `@keyframes` rule.
+        const KEYFRAMES = 1 << (CssRuleType::Keyframes as u16);
+        /// Inside a `@keyframe` rule (a single keyframe of a `@keyframes` rule).
+        const KEYFRAME = 1 << (CssRuleType::Keyframe as u16);
+        /// Inside a `@media` rule.
+        const MEDIA = 1 << (CssRuleType::Media as u16);
+        /// Inside a `@supports` rule.
+        const SUPPORTS = 1 << (CssRuleType::Supports as u16);
+        /// Inside an `@import` rule.
+        const IMPORT = 1 << (CssRuleType::Import as u16);
+        /// Inside a `@font-face` rule.
+        const FONT_FACE = 1 << (CssRuleType::FontFace as u16);
+        /// Inside a `@page` rule.
+        const PAGE = 1 << (CssRuleType::Page as u16);
+        /// Inside a margin at-rule (e.g. `@top-left` within a `@page` rule).
+        const MARGIN = 1 << (CssRuleType::Margin as u16);
+        /// Inside a `@namespace` rule.
+        const NAMESPACE = 1 << (CssRuleType::Namespace as u16);
+        /// Inside a `@viewport` rule.
+        const VIEWPORT = 1 << (CssRuleType::Viewport as u16);
+        /// Inside a `@counter-style` rule.
+        const COUNTER_STYLE = 1 << (CssRuleType::CounterStyle as u16);
+        /// Inside a `@font-feature-values` rule.
+        const FONT_FEATURE_VALUES = 1 << (CssRuleType::FontFeatureValues as u16);
+        /// Inside a `@document` rule.
+        const DOCUMENT = 1 << (CssRuleType::Document as u16);
+    }
+}
+
+impl CssRuleTypes {
+    /// Returns the set containing only `rule_type`, or the empty set if
+    /// `rule_type` is `None`.
+    ///
+    /// Panics (in debug builds) if `rule_type` is a known `CssRuleType`
+    /// variant that isn't represented in `CssRuleTypes`, since silently
+    /// truncating it away would make `contains` vacuously true for it.
+    fn from_rule_type(rule_type: Option<CssRuleType>) -> Self {
+        match rule_type {
+            Some(rule_type) => {
+                let bit = 1 << (rule_type as u16);
+                debug_assert_eq!(
+                    bit & !CssRuleTypes::all().bits(), 0,
+                    "CssRuleType variant has no matching CssRuleTypes flag",
+                );
+                CssRuleTypes::from_bits_truncate(bit)
+            }
+            None => CssRuleTypes::empty(),
+        }
+    }
+}
+
 /// Asserts that all ParsingMode flags have a matching ParsingMode value in gecko.
 #[cfg(feature = "gecko")]
 #[inline]
@@ -38,12 +234,6 @@ pub fn assert_parsing_mode_match() {
     }
 }
 
-/// The context required to report a parse error.
-pub struct ParserErrorContext<'a, R: 'a> {
-    /// An error reporter to report syntax errors.
-    pub error_reporter: &'a R,
-}
-
 /// The data that the parser needs from outside in order to parse a stylesheet.
 pub struct ParserContext<'a> {
     /// The `Origin` of the stylesheet, whether it's a user, author or
@@ -53,6 +243,14 @@ pub struct ParserContext<'a> {
     pub url_data: &'a UrlExtraData,
     /// The current rule type, if any.
     pub rule_type: Option<CssRuleType>,
+    /// The set of every rule type we have descended through to reach the
+    /// current point in the stylesheet, including `rule_type` itself.
+    ///
+    /// Unlike `rule_type`, which is overwritten each time we descend into a
+    /// nested at-rule, this keeps accumulating so a value parser can ask
+    /// whether it is anywhere inside, say, a `@keyframes` block, even if the
+    /// innermost enclosing rule is something else (e.g. a single keyframe).
+    pub rule_types: CssRuleTypes,
     /// Line number offsets for inline stylesheets
     pub line_number_offset: u64,
     /// The mode to use when parsing.
@@ -61,6 +259,29 @@ pub struct ParserContext<'a> {
     pub quirks_mode: QuirksMode,
     /// The currently active namespaces.
     pub namespaces: Option<&'a Namespaces>,
+    /// An error reporter to report syntax errors, if we care about them.
+    ///
+    /// Callers that don't care about diagnostics (CSSOM `@supports`
+    /// evaluation, speculative parses, off-main-thread parsing) can pass
+    /// `None` here and skip all reporting cost.
+    pub error_reporter: Option<&'a ParseErrorReporter>,
+    /// The use counters we should record property and feature parses into,
+    /// if the embedder cares about that telemetry.
+    pub use_counters: Option<&'a UseCounters>,
+    /// The CSS Nesting state: whether we're inside a style rule, and so
+    /// whether a nested style rule's prelude should be parsed as a relative
+    /// selector list anchored on the implied parent `&`.
+    pub nesting_context: NestingContext,
+    /// A caller-owned buffer to accumulate errors into, for a collect-all-
+    /// errors parsing mode.
+    ///
+    /// Unlike `error_reporter`, which reports each error as a fire-and-
+    /// forget side effect and then forgets it, this keeps every
+    /// `ContextualParseError` (with its line-number-offset adjustment
+    /// already applied) around for the caller to inspect once the whole
+    /// sheet has been parsed -- useful for a linter or editor integration
+    /// that wants to render every diagnostic at once.
+    pub error_sink: Option<&'a RefCell<Vec<(SourceLocation, ContextualParseError)>>>,
 }
 
 impl<'a> ParserContext<'a> {
@@ -71,18 +292,91 @@ impl<'a> ParserContext<'a> {
         rule_type: Option<CssRuleType>,
         parsing_mode: ParsingMode,
         quirks_mode: QuirksMode,
+    ) -> ParserContext<'a> {
+        Self::new_with_error_reporter_and_use_counters(
+            stylesheet_origin,
+            url_data,
+            rule_type,
+            parsing_mode,
+            quirks_mode,
+            None,
+            None,
+        )
+    }
+
+    /// Create a parser context with an optional error reporter.
+    pub fn new_with_error_reporter(
+        stylesheet_origin: Origin,
+        url_data: &'a UrlExtraData,
+        rule_type: Option<CssRuleType>,
+        parsing_mode: ParsingMode,
+        quirks_mode: QuirksMode,
+        error_reporter: Option<&'a ParseErrorReporter>,
+    ) -> ParserContext<'a> {
+        Self::new_with_error_reporter_and_use_counters(
+            stylesheet_origin,
+            url_data,
+            rule_type,
+            parsing_mode,
+            quirks_mode,
+            error_reporter,
+            None,
+        )
+    }
+
+    /// Create a parser context with an optional error reporter and an
+    /// optional set of use counters to record property/feature parses into.
+    pub fn new_with_error_reporter_and_use_counters(
+        stylesheet_origin: Origin,
+        url_data: &'a UrlExtraData,
+        rule_type: Option<CssRuleType>,
+        parsing_mode: ParsingMode,
+        quirks_mode: QuirksMode,
+        error_reporter: Option<&'a ParseErrorReporter>,
+        use_counters: Option<&'a UseCounters>,
     ) -> ParserContext<'a> {
         ParserContext {
             stylesheet_origin: stylesheet_origin,
             url_data: url_data,
             rule_type: rule_type,
+            rule_types: CssRuleTypes::from_rule_type(rule_type),
             line_number_offset: 0u64,
             parsing_mode: parsing_mode,
             quirks_mode: quirks_mode,
             namespaces: None,
+            error_reporter: error_reporter,
+            use_counters: use_counters,
+            nesting_context: NestingContext::none(),
+            error_sink: None,
         }
     }
 
+    /// Create a parser context that accumulates every error it encounters,
+    /// together with its adjusted `SourceLocation`, into `error_sink` rather
+    /// than reporting them live.
+    ///
+    /// This is meant for embedders -- a linter or editor integration -- that
+    /// want the full ordered list of diagnostics once a sheet has finished
+    /// parsing.
+    pub fn new_with_error_sink(
+        stylesheet_origin: Origin,
+        url_data: &'a UrlExtraData,
+        rule_type: Option<CssRuleType>,
+        parsing_mode: ParsingMode,
+        quirks_mode: QuirksMode,
+        error_sink: &'a RefCell<Vec<(SourceLocation, ContextualParseError)>>,
+    ) -> ParserContext<'a> {
+        let mut context = Self::new(
+            stylesheet_origin,
+            url_data,
+            rule_type,
+            parsing_mode,
+            quirks_mode,
+        );
+        context.error_sink = Some(error_sink);
+        context
+    }
+
     /// Create a parser context for on-the-fly parsing in CSSOM
     pub fn new_for_cssom(
         url_data: &'a UrlExtraData,
@@ -100,19 +394,42 @@ impl<'a> ParserContext<'a> {
     }
 
     /// Create a parser context based on a previous context, but with a modified rule type.
+    ///
+    /// The new rule type is OR'd into the inherited set of ancestor rule
+    /// types, rather than replacing it, so a parser further down the tree
+    /// can still tell whether it is nested inside, e.g., a `@keyframes`
+    /// block.
+    ///
+    /// When descending into a style rule, whether top-level or nested, this
+    /// also records that any
+    /// further-nested style rule's prelude should be parsed as a relative
+    /// selector list anchored on the implied parent `&`; otherwise the
+    /// nesting state is inherited unchanged.
     pub fn new_with_rule_type(
         context: &'a ParserContext,
         rule_type: CssRuleType,
         namespaces: &'a Namespaces,
     ) -> ParserContext<'a> {
+        let nesting_context = if rule_type == CssRuleType::Style {
+            NestingContext {
+                parse_relative: ParseRelative::ForNesting,
+            }
+        } else {
+            context.nesting_context
+        };
         ParserContext {
             stylesheet_origin: context.stylesheet_origin,
             url_data: context.url_data,
             rule_type: Some(rule_type),
+            rule_types: context.rule_types | CssRuleTypes::from_rule_type(Some(rule_type)),
             line_number_offset: context.line_number_offset,
             parsing_mode: context.parsing_mode,
             quirks_mode: context.quirks_mode,
             namespaces: Some(namespaces),
+            error_reporter: context.error_reporter,
+            use_counters: context.use_counters,
+            nesting_context: nesting_context,
+            error_sink: context.error_sink,
         }
     }
 
@@ -128,10 +445,15 @@ impl<'a> ParserContext<'a> {
             stylesheet_origin: stylesheet_origin,
             url_data: url_data,
             rule_type: None,
+            rule_types: CssRuleTypes::empty(),
             line_number_offset: line_number_offset,
             parsing_mode: parsing_mode,
             quirks_mode: quirks_mode,
             namespaces: None,
+            error_reporter: None,
+            use_counters: None,
+            nesting_context: NestingContext::none(),
+            error_sink: None,
         }
     }
 
@@ -140,18 +462,67 @@ impl<'a> ParserContext<'a> {
         self.rule_type.expect("Rule type expected, but none was found.")
     }
 
-    /// Record a CSS parse error with this context’s error reporting.
-    pub fn log_css_error<R>(&self,
-                            context: &ParserErrorContext<R>,
-                            location: SourceLocation,
-                            error: ContextualParseError)
-        where R: ParseErrorReporter
-    {
+    /// Returns whether we are anywhere inside a rule of the given type,
+    /// including but not limited to the innermost enclosing rule.
+    pub fn rule_types_contains(&self, rule_type: CssRuleType) -> bool {
+        self.rule_types.contains(CssRuleTypes::from_rule_type(Some(rule_type)))
+    }
+
+    /// The relative-selector parse mode that should be used for a selector
+    /// list parsed at this point in the stylesheet.
+    pub fn parse_relative(&self) -> ParseRelative {
+        self.nesting_context.parse_relative()
+    }
+
+    /// Whether an implicit `&` scope is active, i.e. whether a nested style
+    /// rule's selector list should be parsed as a relative selector list
+    /// anchored on the parent.
+    ///
+    /// Named after the parse mode, not the enclosing construct: this is
+    /// `true` whenever `parse_relative()` isn't `No`, whatever form of
+    /// relative-selector nesting that turns out to be.
+    pub fn nesting_active(&self) -> bool {
+        self.nesting_context.is_nested()
+    }
+
+    /// Record a CSS parse error with this context's error reporting.
+    ///
+    /// If this context has an `error_sink`, the error is appended there
+    /// instead of being reported live, so a collect-all-errors caller gets
+    /// the full ordered list back once the sheet has finished parsing.
+    /// Otherwise it's reported through `error_reporter`, if any. Does
+    /// nothing if neither is set, so callers that don't care about
+    /// diagnostics can skip the cost of constructing one.
+    pub fn log_css_error(&self, location: SourceLocation, error: ContextualParseError) {
         let location = SourceLocation {
             line: location.line + self.line_number_offset as u32,
             column: location.column,
         };
-        context.error_reporter.report_error(self.url_data, location, error)
+
+        if let Some(sink) = self.error_sink {
+            sink.borrow_mut().push((location, error));
+            return;
+        }
+
+        if let Some(error_reporter) = self.error_reporter {
+            error_reporter.report_error(self.url_data, location, error);
+        }
+    }
+
+    /// Record that the property with the given id was successfully parsed,
+    /// if this context is tracking use counters.
+    pub fn record_property_use(&self, id: CounterId) {
+        if let Some(counters) = self.use_counters {
+            counters.record_property(id);
+        }
+    }
+
+    /// Record that the at-rule/feature with the given id was successfully
+    /// parsed, if this context is tracking use counters.
+    pub fn record_feature_use(&self, id: CounterId) {
+        if let Some(counters) = self.use_counters {
+            counters.record_feature(id);
+        }
     }
 }
 